@@ -5,19 +5,31 @@ use crate::match_tree::{
 };
 use crate::matcher::{KindMatcher, KindMatcherError, Matcher};
 use crate::ts_parser::TSParseError;
-use crate::{meta_var::MetaVarEnv, Node, Root};
+use crate::{
+  meta_var::{MetaVarEnv, MetaVariable},
+  Node, NodeMatch, Root,
+};
 
 use bit_set::BitSet;
 use thiserror::Error;
 
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
 /// Pattern style specify how we find the ast node to match, assuming pattern text's root is `Program`
 /// the effective AST node to match is either
 #[derive(Clone)]
 enum PatternStyle<L: Language> {
   /// single non-program ast node, notwithstanding MISSING node.
   Single,
-  /// multiple nodes as direct children of Program
+  /// multiple nodes as direct children of Program, matched positionally
   Multiple,
+  /// multiple nodes as direct children of Program, matched as an unordered
+  /// multiset against the candidate node and its following siblings. Only these
+  /// top-level children are reordered; children nested inside a single pattern
+  /// node (e.g. the members of a `class { ... }` pattern) still match positionally.
+  Unordered,
   /// sub AST node specified by user in contextual pattern
   /// e.g. in js`class { $F }` we set selector to public_field_definition
   Selector(KindMatcher<L>),
@@ -27,6 +39,10 @@ enum PatternStyle<L: Language> {
 pub struct Pattern<L: Language> {
   pub(crate) root: Root<L>,
   style: PatternStyle<L>,
+  /// Per-metavariable sub-matchers. A captured node bound to `$NAME` must also
+  /// satisfy `constraints[NAME]`, mirroring rust-analyzer SSR's placeholder kinds.
+  /// Boxed behind an `Option` so the field takes a single pointer.
+  constraints: Option<Box<HashMap<String, Arc<dyn Matcher<L>>>>>,
 }
 
 #[derive(Debug, Error)]
@@ -63,16 +79,38 @@ impl<L: Language> Pattern<L> {
     let style = if is_single_node(&goal.inner) {
       PatternStyle::Single
     } else {
-      return Err(PatternError::MultipleNode(src.into()));
-      // PatternStyle::Multiple
+      PatternStyle::Multiple
     };
-    Ok(Self { root, style })
+    Ok(Self {
+      root,
+      style,
+      constraints: None,
+    })
   }
 
   pub fn new(src: &str, lang: L) -> Self {
     Self::try_new(src, lang).unwrap()
   }
 
+  /// Build a pattern whose metavariables carry additional sub-matchers. A node
+  /// captured by `$NAME` must also match `constraints[NAME]`, so e.g. `$A` can be
+  /// restricted to `call_expression` nodes only.
+  pub fn with_constraints(
+    src: &str,
+    lang: L,
+    constraints: HashMap<String, Box<dyn Matcher<L>>>,
+  ) -> Result<Self, PatternError> {
+    let mut pattern = Self::try_new(src, lang)?;
+    if !constraints.is_empty() {
+      let map = constraints
+        .into_iter()
+        .map(|(name, matcher)| (name, Arc::from(matcher)))
+        .collect();
+      pattern.constraints = Some(Box::new(map));
+    }
+    Ok(pattern)
+  }
+
   pub fn contextual(context: &str, selector: &str, lang: L) -> Result<Self, PatternError> {
     let processed = lang.pre_process_pattern(context);
     let root = Root::try_new(&processed, lang.clone())?;
@@ -87,9 +125,58 @@ impl<L: Language> Pattern<L> {
     Ok(Self {
       root,
       style: PatternStyle::Selector(kind_matcher),
+      constraints: None,
     })
   }
 
+  /// Like [`Pattern::try_new`], but matches a multi-node pattern's children as an
+  /// unordered multiset. Single-node and contextual patterns are left untouched:
+  /// their nested children, such as a class body's members, match positionally.
+  pub fn unordered(src: &str, lang: L) -> Result<Self, PatternError> {
+    let mut pattern = Self::try_new(src, lang)?;
+    if matches!(pattern.style, PatternStyle::Multiple) {
+      pattern.style = PatternStyle::Unordered;
+    }
+    Ok(pattern)
+  }
+
+  /// Match this pattern's children against `node` and its following siblings as an
+  /// unordered multiset: every pattern child must claim a distinct candidate, and
+  /// metavariable bindings stay consistent across the committed assignment.
+  fn match_unordered<'tree>(
+    &self,
+    node: Node<'tree, L>,
+    env: &mut MetaVarEnv<'tree, L>,
+  ) -> Option<Node<'tree, L>> {
+    let mut goals: Vec<_> = self.root.root().children().collect();
+    // process specific (literal) goals before metavariable goals: a metavar
+    // matches any candidate, so letting it claim first could starve a literal goal
+    // of its only counterpart even when a valid assignment exists.
+    goals.sort_by_key(|goal| self.is_meta_var_child(goal));
+    let candidates: Vec<_> = self.multi_node_candidates(&node).collect();
+    let mut consumed = vec![false; candidates.len()];
+    for goal in &goals {
+      let mut claimed = false;
+      for (i, cand) in candidates.iter().enumerate() {
+        if consumed[i] {
+          continue;
+        }
+        // try the pairing on a copy of the env so a failed trial leaves no bindings
+        let mut trial = env.clone();
+        if match_node_non_recursive(goal, cand.clone(), &mut trial).is_some() {
+          *env = trial;
+          consumed[i] = true;
+          claimed = true;
+          break;
+        }
+      }
+      if !claimed {
+        return None;
+      }
+    }
+    Some(node)
+  }
+
   fn single_matcher(&self) -> Node<L> {
     debug_assert!(matches!(self.style, PatternStyle::Single));
     let root = self.root.root();
@@ -113,6 +200,59 @@ impl<L: Language> Pattern<L> {
       .expect("contextual match should succeed")
   }
 
+  /// Whether a `Program` child is a bare metavariable statement, e.g. `$A` or the
+  /// semicolon-terminated `$A;`. We descend through wrapper statements to the
+  /// significant inner node before testing, so a metavar hidden under an
+  /// `expression_statement` (whose trailing `;` defeats [`is_single_node`]) is still
+  /// recognized.
+  fn is_meta_var_child(&self, child: &Node<L>) -> bool {
+    let mut inner = child.inner;
+    loop {
+      // `$A;` holds its only named child followed by a trailing `;`
+      let semicolon_terminated =
+        inner.child_count() == 2 && inner.child(1).map_or(false, |c| c.kind() == ";");
+      if is_single_node(&inner) || semicolon_terminated {
+        inner = inner.child(0).unwrap();
+      } else {
+        break;
+      }
+    }
+    let node = Node {
+      inner,
+      root: &self.root,
+    };
+    node.is_leaf() && extract_var_from_node(&node).is_some()
+  }
+
+  /// The kind id used to index `Program`'s children during search. For a multi-node
+  /// pattern we key off the first child that is not a bare metavariable, since a
+  /// leading `$A` would match any node kind and defeat the kind-based pre-filter.
+  fn multi_node_kind(&self) -> u16 {
+    let root = self.root.root();
+    for child in root.children() {
+      if self.is_meta_var_child(&child) {
+        continue;
+      }
+      return child.kind_id();
+    }
+    // every child is a metavariable, fall back to the first child's kind
+    root.child(0).expect("must have content").kind_id()
+  }
+
+  /// Candidate kinds contributed by the constraint on a bare `$A` pattern's sole
+  /// wildcard. Returns `None` (full-tree scan) when that metavariable carries no
+  /// constraint or its constraint is itself unconstrained; only the constraint keyed
+  /// to the wildcard's own name narrows the search, so a stray constraint on a
+  /// different name cannot cause false negatives.
+  fn wildcard_constraint_kinds(&self) -> Option<BitSet> {
+    let constraints = self.constraints.as_ref()?;
+    let name = match extract_var_from_node(&self.single_matcher())? {
+      MetaVariable::Capture(name, _) => name,
+      _ => return None,
+    };
+    constraints.get(&name)?.potential_kinds()
+  }
+
   // TODO: find a better name. also what a signature LOL
   fn multi_node_candidates<'t: 'a, 'a>(
     &self,
@@ -129,7 +269,7 @@ impl<L: Language> Matcher<L> for Pattern<L> {
     node: Node<'tree, L>,
     env: &mut MetaVarEnv<'tree, L>,
   ) -> Option<Node<'tree, L>> {
-    match &self.style {
+    let matched = match &self.style {
       PatternStyle::Single => {
         let matcher = self.single_matcher();
         match_node_non_recursive(&matcher, node, env)
@@ -140,26 +280,35 @@ impl<L: Language> Matcher<L> for Pattern<L> {
         env,
       )
       .map(|_| node),
+      PatternStyle::Unordered => self.match_unordered(node, env),
       PatternStyle::Selector(kind) => {
         let matcher = self.kind_matcher(kind);
         match_node_non_recursive(&matcher, node, env)
       }
+    }?;
+    if let Some(constraints) = &self.constraints {
+      for (name, matcher) in constraints.iter() {
+        // re-run the sub-matcher against the captured node; an unbound name has
+        // no node to constrain, so it is simply ignored.
+        let Some(captured) = env.get_match(name).cloned() else {
+          continue;
+        };
+        matcher.match_node_with_env(captured, env)?;
+      }
     }
+    Some(matched)
   }
 
   fn potential_kinds(&self) -> Option<bit_set::BitSet> {
     let kind = match &self.style {
       PatternStyle::Selector(kind) => return kind.potential_kinds(),
-      PatternStyle::Multiple => self
-        .root
-        .root()
-        .child(0)
-        .expect("must have content")
-        .kind_id(),
+      PatternStyle::Multiple | PatternStyle::Unordered => self.multi_node_kind(),
       PatternStyle::Single => {
         let matcher = self.single_matcher();
         if matcher.is_leaf() && extract_var_from_node(&matcher).is_some() {
-          return None;
+          // a bare wildcard normally scans the whole tree, but a kind constraint
+          // on it lets us narrow the candidate kinds back down.
+          return self.wildcard_constraint_kinds();
         }
         matcher.kind_id()
       }
@@ -178,6 +327,10 @@ impl<L: Language> Matcher<L> for Pattern<L> {
         self.root.root().children(),
         self.multi_node_candidates(&node),
       )?,
+      // unordered bindings may be reordered and non-contiguous, so a positional
+      // span would not cover the actually-matched nodes; refuse to report a
+      // replacement length rather than risk corrupting surrounding text.
+      PatternStyle::Unordered => return None,
     };
     Some(end - start)
   }
@@ -187,17 +340,69 @@ impl<L: Language> std::fmt::Debug for Pattern<L> {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match &self.style {
       PatternStyle::Single => write!(f, "{}", self.single_matcher().to_sexp()),
-      PatternStyle::Multiple => write!(f, "{}", self.root.root().to_sexp()),
+      PatternStyle::Multiple | PatternStyle::Unordered => {
+        write!(f, "{}", self.root.root().to_sexp())
+      }
       PatternStyle::Selector(kind) => write!(f, "{}", self.kind_matcher(kind).to_sexp()),
     }
   }
 }
 
+/// Source ranges bound to `env`'s metavariables. These are the placeholder
+/// expansions a nested match may be discarded for falling inside.
+fn meta_var_ranges<L: Language>(env: &MetaVarEnv<'_, L>) -> Vec<Range<usize>> {
+  env
+    .get_matched_variables()
+    .filter_map(|var| match var {
+      MetaVariable::Capture(name, _) => env.get_match(&name).map(|n| n.range()),
+      MetaVariable::MultiCapture(name) => {
+        let matches = env.get_multiple_matches(&name);
+        Some(matches.first()?.range().start..matches.last()?.range().end)
+      }
+      _ => None,
+    })
+    .collect()
+}
+
+/// Filter out matches nested inside another match's metavariable region, so
+/// rewriting an outer match does not clobber text an inner match also claimed
+/// (e.g. `foo($A)` matching `foo(foo(x))` at both calls keeps only the outer).
+///
+/// Matches are considered outermost-first; a later match is dropped only when its
+/// range is fully contained in a placeholder expansion of an already-accepted
+/// match. Matches overlapping the fixed, non-metavar part of an outer match are
+/// kept, and among identical ranges the first (outermost) wins.
+pub fn remove_nested_matches<'tree, L: Language>(
+  matches: impl IntoIterator<Item = NodeMatch<'tree, L>>,
+) -> Vec<NodeMatch<'tree, L>> {
+  let mut matches: Vec<_> = matches.into_iter().collect();
+  // earliest start first, and on a tie the wider (outer) range first
+  matches.sort_by_key(|m| {
+    let range = m.range();
+    (range.start, std::cmp::Reverse(range.end))
+  });
+  let mut accepted: Vec<NodeMatch<'tree, L>> = Vec::new();
+  let mut regions: Vec<Range<usize>> = Vec::new();
+  'next: for nm in matches {
+    let range = nm.range();
+    for region in &regions {
+      if region.start <= range.start && range.end <= region.end {
+        continue 'next;
+      }
+    }
+    if accepted.iter().any(|a| a.range() == range) {
+      continue 'next;
+    }
+    regions.extend(meta_var_ranges(nm.get_env()));
+    accepted.push(nm);
+  }
+  accepted
+}
+
 #[cfg(test)]
 mod test {
   use super::*;
   use crate::language::Tsx;
-  use std::collections::HashMap;
 
   fn pattern_node(s: &str) -> Root<Tsx> {
     Root::new(s, Tsx)
@@ -329,6 +534,32 @@ mod test {
     assert!(pattern.potential_kinds().is_none());
   }
 
+  fn call_constraint() -> HashMap<String, Box<dyn Matcher<Tsx>>> {
+    let mut constraints: HashMap<String, Box<dyn Matcher<Tsx>>> = HashMap::new();
+    constraints.insert(
+      "A".into(),
+      Box::new(KindMatcher::try_new("call_expression", Tsx).expect("test")),
+    );
+    constraints
+  }
+
+  #[test]
+  fn test_constrained_wildcard() {
+    let pattern = Pattern::with_constraints("$A", Tsx, call_constraint()).expect("test");
+    let cand = pattern_node("foo()");
+    assert!(pattern.find_node(cand.root()).is_some());
+    let cand = pattern_node("123");
+    assert!(pattern.find_node(cand.root()).is_none());
+  }
+
+  #[test]
+  fn test_constrained_wildcard_potential_kinds() {
+    let pattern = Pattern::with_constraints("$A", Tsx, call_constraint()).expect("test");
+    let kind = get_kind("call_expression");
+    let kinds = pattern.potential_kinds().expect("should have kinds");
+    assert!(kinds.contains(kind));
+  }
+
   #[test]
   fn test_contextual_potential_kinds() {
     let pattern =
@@ -349,7 +580,6 @@ mod test {
   }
 
   #[test]
-  #[ignore]
   fn test_multi_node_pattern() {
     let pattern = Pattern::new("a;b;c;", Tsx);
     let kinds = pattern.potential_kinds().expect("should have kinds");
@@ -358,7 +588,6 @@ mod test {
   }
 
   #[test]
-  #[ignore]
   fn test_multi_node_meta_var() {
     let env = match_env("a;$B;c", "a;b;c");
     assert_eq!(env["B"], "b");
@@ -366,8 +595,36 @@ mod test {
     assert_eq!(env["B"], "1+2+3");
   }
 
+  #[test]
+  fn test_unordered_pattern() {
+    let pattern = Pattern::unordered("a;b;c", Tsx).expect("test");
+    // same members in a different order still match
+    assert!(pattern.find_node(pattern_node("b;c;a;").root()).is_some());
+    // a missing member fails the assignment
+    assert!(pattern.find_node(pattern_node("a;b;").root()).is_none());
+  }
+
+  #[test]
+  fn test_unordered_meta_var() {
+    let pattern = Pattern::unordered("$X;b", Tsx).expect("test");
+    let nm = pattern.find_node(pattern_node("b;a").root()).expect("test");
+    let env = HashMap::from(nm.get_env().clone());
+    assert_eq!(env["X"], "a");
+  }
+
+  #[test]
+  fn test_remove_nested_matches() {
+    let pattern = Pattern::new("foo($A)", Tsx);
+    let cand = pattern_node("foo(foo(x))");
+    let root = cand.root();
+    let matches: Vec<_> = root.find_all(&pattern).collect();
+    assert_eq!(matches.len(), 2, "both the outer and inner call match");
+    let filtered = remove_nested_matches(matches);
+    assert_eq!(filtered.len(), 1, "the inner call is nested in $A and dropped");
+  }
+
   #[test]
   fn test_pattern_size() {
-    assert_eq!(std::mem::size_of::<Pattern<Tsx>>(), 40);
+    assert_eq!(std::mem::size_of::<Pattern<Tsx>>(), 48);
   }
 }
\ No newline at end of file